@@ -1,55 +1,264 @@
-use serde::{Deserialize, Serialize};
+#[cfg(feature = "watch")]
+use notify::{EventKind, RecursiveMode, Watcher};
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
 use shuttle_runtime::async_trait;
 use shuttle_service::{error::CustomError, Factory, ResourceBuilder, Type};
 use shuttle_static_folder::{Paths, StaticFolder};
-use std::path::PathBuf;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+#[cfg(feature = "watch")]
+use std::collections::BTreeSet;
+use std::path::{Component, Path, PathBuf};
+use std::sync::Mutex;
 
 const DEFAULT_FOLDER: &str = ".env";
 const DEFAULT_ENV_PROD: &str = ".env";
 
-#[derive(Serialize)]
+static REPORTS: Mutex<BTreeMap<PathBuf, LoadReport>> = Mutex::new(BTreeMap::new());
+
+#[cfg(feature = "watch")]
+static WATCHED_FILES: Mutex<BTreeSet<PathBuf>> = Mutex::new(BTreeSet::new());
+
 pub struct EnvVars<'a> {
     /// The folder to reach at runtime. Defaults to `.env`.
-    folder: &'a str,
+    folder: Cow<'a, Path>,
     /// The name of the file to use in production. Defaults to `.env`.
-    env_prod: &'a str,
+    env_prod: PathArg<'a>,
     /// The name of the file to use in local.
-    env_local: Option<&'a str>,
-    /// The static provider to use.
-    static_provider: Option<shuttle_static_folder::StaticFolder<'a>>,
+    env_local: Option<PathArg<'a>>,
+    /// The paths given to the `*_path` setters, referenced by `PathArg::Owned`.
+    owned_paths: Vec<PathBuf>,
+    /// Whether to reload the env vars when the local file changes.
+    #[cfg(feature = "watch")]
+    watch: bool,
+    /// Whether to read the env file from the storage folder instead of the static folder.
+    from_storage: bool,
+    /// The keys that are expected to be set after loading the env file.
+    required: &'a [&'a str],
+}
+
+/// A path given to the builder.
+/// Owned paths live in `EnvVars::owned_paths` so the `&str` setters can stay `const`.
+#[derive(Clone, Copy)]
+enum PathArg<'a> {
+    Borrowed(&'a str),
+    Owned(usize),
+}
+
+impl Serialize for EnvVars<'_> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let len = 5 + usize::from(cfg!(feature = "watch"));
+        let mut state = serializer.serialize_struct("EnvVars", len)?;
+        state.serialize_field("folder", &self.folder)?;
+        state.serialize_field("env_prod", self.env_prod_file())?;
+        state.serialize_field("env_local", &self.env_local_file())?;
+        #[cfg(feature = "watch")]
+        state.serialize_field("watch", &self.watch)?;
+        state.serialize_field("from_storage", &self.from_storage)?;
+        state.serialize_field("required", &self.required)?;
+        state.end()
+    }
+}
+
+/// Summary of the env vars load. It only contains keys, never values, so it's safe to share.
+#[derive(Debug, Clone, Serialize)]
+pub struct LoadReport {
+    /// The environment the env vars were loaded in: `local` or `production`.
+    pub environment: String,
+    /// The env file the env vars were loaded from.
+    pub source_path: PathBuf,
+    /// Whether the env file was found.
+    pub file_found: bool,
+    /// The keys set from the env file.
+    pub loaded_keys: Vec<String>,
+    /// The required keys that are not set after loading the env file.
+    pub missing_required: Vec<String>,
+    /// The keys of the env file that were already set and therefore kept their previous value.
+    pub kept_existing: Vec<String>,
+}
+
+impl LoadReport {
+    /// Returns the report of the last load of the given resource, even if it failed.
+    /// The resource is the `PathBuf` returned by `EnvVars`.
+    pub fn get(resource: impl AsRef<Path>) -> Option<Self> {
+        REPORTS
+            .lock()
+            .ok()
+            .and_then(|reports| reports.get(resource.as_ref()).cloned())
+    }
+
+    /// Returns the reports of the last load of every resource.
+    pub fn all() -> Vec<Self> {
+        REPORTS
+            .lock()
+            .map(|reports| reports.values().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    fn store(&self, resource: &Path) {
+        if let Ok(mut reports) = REPORTS.lock() {
+            reports.insert(resource.to_path_buf(), self.clone());
+        }
+    }
+}
+
+/// The keys found in an env file while loading it.
+#[derive(Default)]
+struct LoadedKeys {
+    /// The keys set from the env file.
+    loaded: Vec<String>,
+    /// The keys that were already set and kept their previous value.
+    existing: Vec<String>,
 }
 
 #[derive(Debug)]
-pub struct EnvError(dotenvy::Error);
+pub struct EnvError {
+    /// The env file that failed to load.
+    path: PathBuf,
+    /// The underlying dotenvy error.
+    error: dotenvy::Error,
+    /// The 1-based line number of the offending line in the env file, if any.
+    /// dotenvy doesn't report it, so it's a best guess found by matching the line content.
+    line_number: Option<usize>,
+}
+
+impl EnvError {
+    fn new(path: &Path, error: dotenvy::Error) -> Self {
+        let line_number = match &error {
+            dotenvy::Error::LineParse(line, _) => Self::find_line(path, line),
+            _ => None,
+        };
+
+        Self {
+            path: path.to_path_buf(),
+            error,
+            line_number,
+        }
+    }
+
+    /// Finds the 1-based line number where the given line starts in the env file.
+    /// Quoted values may span several lines, so all of them must match.
+    fn find_line(path: &Path, line: &str) -> Option<usize> {
+        let line: Vec<&str> = line.lines().collect();
+        if line.is_empty() {
+            return None;
+        }
+
+        let content = std::fs::read_to_string(path).ok()?;
+        let content: Vec<&str> = content.lines().collect();
+        content
+            .windows(line.len())
+            .position(|lines| lines == line.as_slice())
+            .map(|index| index + 1)
+    }
+}
 
 impl<'a> EnvVars<'a> {
     #[must_use]
     pub fn folder(mut self, folder: &'a str) -> Self {
-        self.folder = folder;
-        self.static_provider = self.static_provider.map(|p| p.folder(folder));
+        self.folder = Cow::Borrowed(Path::new(folder));
+        self
+    }
+
+    /// Same as `folder` but accepting any path.
+    #[must_use]
+    pub fn folder_path(mut self, folder: impl AsRef<Path>) -> Self {
+        self.folder = Cow::Owned(folder.as_ref().to_path_buf());
         self
     }
 
     #[must_use]
     pub const fn env_prod(mut self, env_prod: &'a str) -> Self {
-        self.env_prod = env_prod;
+        self.env_prod = PathArg::Borrowed(env_prod);
+        self
+    }
+
+    /// Same as `env_prod` but accepting any path.
+    #[must_use]
+    pub fn env_prod_path(mut self, env_prod: impl AsRef<Path>) -> Self {
+        self.env_prod = self.own_path(env_prod);
         self
     }
 
     #[must_use]
     pub const fn env_local(mut self, env_local: &'a str) -> Self {
-        self.env_local = Some(env_local);
+        self.env_local = Some(PathArg::Borrowed(env_local));
+        self
+    }
+
+    /// Same as `env_local` but accepting any path.
+    #[must_use]
+    pub fn env_local_path(mut self, env_local: impl AsRef<Path>) -> Self {
+        self.env_local = Some(self.own_path(env_local));
+        self
+    }
+
+    fn own_path(&mut self, path: impl AsRef<Path>) -> PathArg<'a> {
+        self.owned_paths.push(path.as_ref().to_path_buf());
+        PathArg::Owned(self.owned_paths.len() - 1)
+    }
+
+    fn resolve_path(&self, path: PathArg<'a>) -> &Path {
+        match path {
+            PathArg::Borrowed(path) => Path::new(path),
+            PathArg::Owned(index) => &self.owned_paths[index],
+        }
+    }
+
+    fn env_prod_file(&self) -> &Path {
+        self.resolve_path(self.env_prod)
+    }
+
+    fn env_local_file(&self) -> Option<&Path> {
+        self.env_local.map(|path| self.resolve_path(path))
+    }
+
+    /// Reloads the env vars every time the local env file changes.
+    /// Only used in local environment, this is a no-op in production.
+    ///
+    /// As in the first load, the keys already set before loading the file are kept.
+    /// The file is watched from a background thread living until the process exits,
+    /// and only one watcher is started per file.
+    ///
+    /// Note that the env vars are set from that thread while the rest of the service
+    /// may be reading them, which is not thread-safe on some platforms (e.g. glibc).
+    /// Don't use it outside local development.
+    #[cfg(feature = "watch")]
+    #[must_use]
+    pub const fn watch(mut self) -> Self {
+        self.watch = true;
+        self
+    }
+
+    /// Reads the env file directly from the storage folder in production,
+    /// skipping the static folder. Useful if the env file is not deployed with the service.
+    #[must_use]
+    pub const fn from_storage(mut self) -> Self {
+        self.from_storage = true;
+        self
+    }
+
+    /// The keys that must be set after loading the env file.
+    /// They're only used to fill `LoadReport::missing_required`, and missing keys are logged,
+    /// but the load doesn't fail because of them.
+    #[must_use]
+    pub const fn required(mut self, required: &'a [&'a str]) -> Self {
+        self.required = required;
         self
     }
 
     pub fn env_file_path(&self, output_dir: Option<&PathBuf>) -> PathBuf {
         output_dir.map_or_else(
-            || self.env_local.unwrap_or("").into(),
-            |dir| dir.join(self.env_prod),
+            || {
+                self.env_local_file()
+                    .unwrap_or_else(|| Path::new(""))
+                    .into()
+            },
+            |dir| dir.join(self.env_prod_file()),
         )
     }
 
-    pub fn load_env_vars(env_file_path: &PathBuf) -> Result<PathBuf, EnvError> {
+    pub fn load_env_vars(env_file_path: &Path) -> Result<PathBuf, EnvError> {
         if env_file_path.as_os_str().is_empty() {
             tracing::info!(?env_file_path, "Is empty!");
             return Ok("".into());
@@ -57,59 +266,275 @@ impl<'a> EnvVars<'a> {
 
         tracing::info!(?env_file_path, "Loading env vars from file");
 
-        dotenvy::from_filename(env_file_path).map_err(|e| {
-            tracing::error!(?e, "Failed to load env vars");
-            EnvError(e)
-        })
+        Self::set_env_vars(env_file_path, &[], &mut LoadedKeys::default())?;
+        Ok(env_file_path.to_path_buf())
+    }
+
+    /// Sets the env vars of the file, collecting the keys in `keys` as they're set.
+    /// Keys already set in the environment are kept unless they're in `owned_keys`.
+    fn set_env_vars(
+        env_file_path: &Path,
+        owned_keys: &[String],
+        keys: &mut LoadedKeys,
+    ) -> Result<(), EnvError> {
+        let to_env_error = |e| {
+            let error = EnvError::new(env_file_path, e);
+            tracing::error!(%error, "Failed to load env vars");
+            error
+        };
+
+        for item in dotenvy::from_filename_iter(env_file_path).map_err(to_env_error)? {
+            let (key, value) = item.map_err(to_env_error)?;
+            // same as dotenvy, the first value wins if a key is repeated
+            if keys.loaded.contains(&key) || keys.existing.contains(&key) {
+                continue;
+            }
+            if owned_keys.contains(&key) || std::env::var_os(&key).is_none() {
+                std::env::set_var(&key, value);
+                keys.loaded.push(key);
+            } else {
+                keys.existing.push(key);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Loads the env vars and stores a `LoadReport` of the load for the given resource,
+    /// available through `LoadReport::get`. The report is stored even if the load fails.
+    fn load_and_report(
+        env_file_path: &Path,
+        resource: &Path,
+        environment: &str,
+        required: &[String],
+        owned_keys: &[String],
+    ) -> Result<LoadReport, EnvError> {
+        let file_found = env_file_path.is_file();
+
+        let mut keys = LoadedKeys::default();
+        let result = if env_file_path.as_os_str().is_empty() {
+            tracing::info!(?env_file_path, "Is empty!");
+            Ok(())
+        } else {
+            tracing::info!(?env_file_path, "Loading env vars from file");
+            Self::set_env_vars(env_file_path, owned_keys, &mut keys)
+        };
+
+        let missing_required: Vec<String> = required
+            .iter()
+            .filter(|key| std::env::var_os(key).is_none())
+            .cloned()
+            .collect();
+
+        if !missing_required.is_empty() {
+            tracing::warn!(?missing_required, "Missing required env vars");
+        }
+
+        let report = LoadReport {
+            environment: environment.to_string(),
+            source_path: env_file_path.to_path_buf(),
+            file_found,
+            loaded_keys: keys.loaded,
+            missing_required,
+            kept_existing: keys.existing,
+        };
+        report.store(resource);
+
+        result.map(|()| report)
+    }
+}
+
+/// Spawns a background thread reloading the env vars every time the env file changes.
+/// Only the keys loaded from the file, or the ones not set yet, are overridden.
+/// Each reload refreshes the `LoadReport` of the env file.
+/// Returns whether a new watcher was started.
+#[cfg(feature = "watch")]
+fn watch_env_file(env_file_path: &Path, required: Vec<String>) -> bool {
+    if env_file_path.as_os_str().is_empty() {
+        tracing::warn!("No local env file to watch");
+        return false;
+    }
+
+    // reports are keyed by the path as given, events come with the canonical one
+    let report_path = env_file_path.to_path_buf();
+    let mut owned_keys = LoadReport::get(&report_path)
+        .map(|report| report.loaded_keys)
+        .unwrap_or_default();
+    let env_file_path = match env_file_path.canonicalize() {
+        Ok(path) => path,
+        Err(e) => {
+            tracing::error!(?e, ?env_file_path, "Failed to resolve env file to watch");
+            return false;
+        }
+    };
+
+    // watching the parent folder as editors usually replace the file instead of writing to it
+    let Some(env_dir) = env_file_path.parent() else {
+        tracing::error!(?env_file_path, "Env file has no parent folder to watch");
+        return false;
+    };
+
+    let Ok(mut watched_files) = WATCHED_FILES.lock() else {
+        tracing::error!("Failed to lock the watched env files");
+        return false;
+    };
+
+    if watched_files.contains(&env_file_path) {
+        tracing::info!(?env_file_path, "Env file is already being watched");
+        return false;
+    }
+
+    let (tx, rx) = std::sync::mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(tx) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            tracing::error!(?e, "Failed to create env file watcher");
+            return false;
+        }
+    };
+
+    if let Err(e) = watcher.watch(env_dir, RecursiveMode::NonRecursive) {
+        tracing::error!(?e, ?env_dir, "Failed to watch env file");
+        return false;
     }
+
+    tracing::info!(?env_file_path, "Watching env file for changes");
+    watched_files.insert(env_file_path.clone());
+
+    std::thread::spawn(move || {
+        // the watcher must live as long as the thread
+        let _watcher = watcher;
+        for event in rx {
+            match event {
+                Ok(event)
+                    if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_))
+                        && event.paths.contains(&env_file_path) =>
+                {
+                    tracing::info!(?env_file_path, "Env file changed, reloading env vars");
+                    if let Err(e) = EnvVars::load_and_report(
+                        &report_path,
+                        &report_path,
+                        "local",
+                        &required,
+                        &owned_keys,
+                    ) {
+                        tracing::error!(%e, "Failed to reload env vars");
+                    }
+                    // keys set before a failure are owned too
+                    let loaded_keys = LoadReport::get(&report_path)
+                        .map(|report| report.loaded_keys)
+                        .unwrap_or_default();
+                    for key in loaded_keys {
+                        if !owned_keys.contains(&key) {
+                            owned_keys.push(key);
+                        }
+                    }
+                }
+                Ok(_) => {}
+                Err(e) => tracing::error!(?e, "Error watching env file"),
+            }
+        }
+    });
+
+    true
+}
+
+/// Applies the same restrictions as the static provider to a folder inside the storage folder.
+fn check_storage_folder(folder: &Path) -> Result<(), shuttle_service::Error> {
+    if folder.has_root() || folder.is_absolute() {
+        return Err(shuttle_service::Error::Custom(CustomError::msg(
+            "Cannot use an absolute path for a storage folder",
+        )));
+    }
+
+    if folder
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Err(shuttle_service::Error::Custom(CustomError::msg(
+            "Cannot traverse out of the storage folder",
+        )));
+    }
+
+    Ok(())
+}
+
+/// Where the env file is read from in production.
+#[derive(Serialize, Deserialize)]
+pub enum Source {
+    /// The env folder is copied by the static provider.
+    Static(Paths),
+    /// The env folder already lives in the storage folder.
+    Storage(PathBuf),
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct ResourceOutput {
-    env_prod: String,
-    env_local: String,
-    paths: Option<Paths>,
+    env_prod: PathBuf,
+    env_local: PathBuf,
+    source: Option<Source>,
+    #[cfg(feature = "watch")]
+    #[serde(default)]
+    watch: bool,
+    #[serde(default)]
+    required: Vec<String>,
 }
 
 impl ResourceOutput {
-    pub fn new(paths: Option<Paths>, env_local: Option<&str>, env_prod: &str) -> Self {
+    pub fn new(source: Option<Source>, env_local: Option<&Path>, env_prod: &Path) -> Self {
         Self {
-            paths,
-            env_local: env_local.unwrap_or("").to_string(),
-            env_prod: env_prod.to_string(),
+            source,
+            env_local: env_local.map(Path::to_path_buf).unwrap_or_default(),
+            env_prod: env_prod.to_path_buf(),
+            #[cfg(feature = "watch")]
+            watch: false,
+            required: Vec::new(),
         }
     }
 
+    #[must_use]
+    fn with_required(mut self, required: &[&str]) -> Self {
+        self.required = required.iter().map(ToString::to_string).collect();
+        self
+    }
+
+    #[cfg(feature = "watch")]
+    #[must_use]
+    const fn with_watch(mut self, watch: bool) -> Self {
+        self.watch = watch;
+        self
+    }
+
     pub fn env_file_path(&self, output_dir: Option<&PathBuf>) -> PathBuf {
-        output_dir.map_or_else(
-            || self.env_local.clone().into(),
-            |dir| dir.join(self.env_prod.clone()),
-        )
+        output_dir.map_or_else(|| self.env_local.clone(), |dir| dir.join(&self.env_prod))
     }
 }
 
 #[async_trait]
 impl<'a> ResourceBuilder<PathBuf> for EnvVars<'a> {
     const TYPE: Type = Type::StaticFolder;
-    type Config = &'a str;
+    type Config = Cow<'a, Path>;
     type Output = ResourceOutput;
 
     fn new() -> Self {
-        let static_provider = shuttle_static_folder::StaticFolder::new().folder(DEFAULT_FOLDER);
         Self {
-            folder: DEFAULT_FOLDER,
-            env_prod: DEFAULT_ENV_PROD,
+            folder: Cow::Borrowed(Path::new(DEFAULT_FOLDER)),
+            env_prod: PathArg::Borrowed(DEFAULT_ENV_PROD),
             env_local: None,
-            static_provider: Some(static_provider),
+            owned_paths: Vec::new(),
+            #[cfg(feature = "watch")]
+            watch: false,
+            from_storage: false,
+            required: &[],
         }
     }
 
-    fn config(&self) -> &&'a str {
+    fn config(&self) -> &Cow<'a, Path> {
         &self.folder
     }
 
     async fn output(
-        mut self,
+        self,
         factory: &mut dyn Factory,
     ) -> Result<Self::Output, shuttle_service::Error> {
         tracing::info!("Calling output function");
@@ -125,47 +550,120 @@ impl<'a> ResourceBuilder<PathBuf> for EnvVars<'a> {
 
         if !is_production {
             tracing::info!("Not in production, loading env vars from file");
-            let resource = ResourceOutput::new(None, self.env_local, self.env_prod);
+            let resource = ResourceOutput::new(None, self.env_local_file(), self.env_prod_file())
+                .with_required(self.required);
+            #[cfg(feature = "watch")]
+            let resource = resource.with_watch(self.watch);
+            return Ok(resource);
+        }
+
+        if self.from_storage {
+            tracing::info!("Skipping static provider, reading env file from storage");
+            check_storage_folder(&self.folder)?;
+            let env_dir = factory.get_storage_path()?.join(&self.folder);
+            let resource = ResourceOutput::new(
+                Some(Source::Storage(env_dir)),
+                self.env_local_file(),
+                self.env_prod_file(),
+            )
+            .with_required(self.required);
             return Ok(resource);
         }
 
         tracing::trace!("Calling Static provider");
-        let static_provider = self
-            .static_provider
-            .take()
-            .expect("Static Provider is missing");
+        // the static provider only accepts a `&str` folder
+        let folder = self.folder.to_str().ok_or_else(|| {
+            shuttle_service::Error::Custom(CustomError::msg(format!(
+                "Env folder is not valid UTF-8: {:?}",
+                self.folder
+            )))
+        })?;
+        let static_provider = StaticFolder::new().folder(folder);
 
         tracing::trace!("Getting paths");
         let paths = static_provider.output(factory).await?;
         tracing::info!("Static provider returned");
 
-        let resource = ResourceOutput::new(Some(paths), self.env_local, self.env_prod);
+        let resource = ResourceOutput::new(
+            Some(Source::Static(paths)),
+            self.env_local_file(),
+            self.env_prod_file(),
+        )
+        .with_required(self.required);
         Ok(resource)
     }
 
     async fn build(build_data: &Self::Output) -> Result<PathBuf, shuttle_service::Error> {
-        if let Some(paths) = build_data.paths.as_ref() {
-            // production environment
-            tracing::info!("build method called for production");
-            let output_dir = StaticFolder::build(paths).await?;
-            tracing::info!("Got output_dir from StaticFolder::build {:?}", output_dir);
-            let env_file_path = build_data.env_file_path(Some(&output_dir));
-            Self::load_env_vars(&env_file_path)?;
-            Ok(output_dir)
-        } else {
-            // development environment
-            tracing::info!("build method called for development");
-            let env_file_path = build_data.env_file_path(None);
-            Self::load_env_vars(&env_file_path)?;
-            Ok(env_file_path)
+        match build_data.source.as_ref() {
+            Some(Source::Static(paths)) => {
+                // production environment
+                tracing::info!("build method called for production");
+                let output_dir = StaticFolder::build(paths).await?;
+                tracing::info!("Got output_dir from StaticFolder::build {:?}", output_dir);
+                let env_file_path = build_data.env_file_path(Some(&output_dir));
+                Self::load_and_report(
+                    &env_file_path,
+                    &output_dir,
+                    "production",
+                    &build_data.required,
+                    &[],
+                )?;
+                Ok(output_dir)
+            }
+            Some(Source::Storage(env_dir)) => {
+                // production environment reading from storage
+                tracing::info!("build method called for production from storage");
+                let env_file_path = build_data.env_file_path(Some(env_dir));
+                Self::load_and_report(
+                    &env_file_path,
+                    env_dir,
+                    "production",
+                    &build_data.required,
+                    &[],
+                )?;
+                Ok(env_dir.clone())
+            }
+            None => {
+                // development environment
+                tracing::info!("build method called for development");
+                let env_file_path = build_data.env_file_path(None);
+                Self::load_and_report(
+                    &env_file_path,
+                    &env_file_path,
+                    "local",
+                    &build_data.required,
+                    &[],
+                )?;
+                #[cfg(feature = "watch")]
+                if build_data.watch {
+                    watch_env_file(&env_file_path, build_data.required.clone());
+                }
+                Ok(env_file_path)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for EnvError {
+    // the offending line is never printed as it may contain secrets
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let path = self.path.display();
+        match (&self.error, self.line_number) {
+            (dotenvy::Error::LineParse(_, index), Some(line_number)) => write!(
+                f,
+                "Cannot load env vars from {path}: parse error, probably at line {line_number} (index {index})"
+            ),
+            (dotenvy::Error::LineParse(..), None) => {
+                write!(f, "Cannot load env vars from {path}: parse error")
+            }
+            (e, _) => write!(f, "Cannot load env vars from {path}: {e}"),
         }
     }
 }
 
 impl From<EnvError> for shuttle_service::Error {
     fn from(error: EnvError) -> Self {
-        let msg = format!("Cannot load env vars: {error:?}");
-        Self::Custom(CustomError::msg(msg))
+        Self::Custom(CustomError::msg(error.to_string()))
     }
 }
 
@@ -588,4 +1086,384 @@ mod tests {
         let output = env_folder.output(&mut factory).await.unwrap();
         let _ = EnvVars::build(&output).await.unwrap();
     }
+
+    #[tokio::test]
+    async fn error_message_contains_file_and_line() {
+        let mut factory = MockFactory::new(false);
+
+        const CONTENT: &str = "MY_VAR7=1\nMY_VAR8=2\nMY VAR9=3\n";
+
+        let local_env_path = factory.build_path().join(".env-broken");
+        fs::write(&local_env_path, CONTENT).unwrap();
+
+        // Call plugin
+        let env_folder = EnvVars::new().env_local(local_env_path.to_str().unwrap());
+
+        let output = env_folder.output(&mut factory).await.unwrap();
+        let error = EnvVars::build(&output).await.unwrap_err().to_string();
+
+        assert!(
+            error.contains(&local_env_path.display().to_string()),
+            "should contain the env file path: {error}"
+        );
+        assert!(
+            error.contains("line 3"),
+            "should contain the offending line number: {error}"
+        );
+        assert!(
+            !error.contains("VAR9"),
+            "should not contain the offending line: {error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn error_line_matches_whole_lines() {
+        let mut factory = MockFactory::new(false);
+
+        const CONTENT: &str = "MY_VAR19=1\nMY\n";
+
+        let local_env_path = factory.build_path().join(".env-broken-prefix");
+        fs::write(&local_env_path, CONTENT).unwrap();
+
+        // Call plugin
+        let env_folder = EnvVars::new().env_local(local_env_path.to_str().unwrap());
+
+        let output = env_folder.output(&mut factory).await.unwrap();
+        let error = EnvVars::build(&output).await.unwrap_err().to_string();
+
+        assert!(
+            error.contains("line 2"),
+            "should contain the offending line number: {error}"
+        );
+    }
+
+    #[test]
+    fn finds_line_of_multiline_values() {
+        let temp_dir = Builder::new().prefix("env_folder").tempdir().unwrap();
+        let env_path = temp_dir.path().join(".env-multiline");
+        fs::write(&env_path, "MY_VAR20=1\nMY_VAR21=\"a\nb\" c\n").unwrap();
+
+        assert_eq!(
+            EnvError::find_line(&env_path, "MY_VAR21=\"a\nb\" c"),
+            Some(2),
+            "should point to the first line of the value"
+        );
+    }
+
+    // Depends on process-wide state: env vars and the set of watched files.
+    // Each run uses a new temp folder and resets its env vars so it can be run again.
+    #[cfg(feature = "watch")]
+    #[tokio::test]
+    async fn reloads_env_vars_if_local_and_watching() {
+        let mut factory = MockFactory::new(false);
+
+        std::env::remove_var("MY_VAR10");
+        std::env::remove_var("MY_VAR23");
+        std::env::set_var("MY_VAR22", "shell");
+        let local_env_path = factory.build_path().join(".env-watch");
+        fs::write(&local_env_path, "MY_VAR10=1\nMY_VAR22=1").unwrap();
+
+        // Call plugin
+        let env_folder = EnvVars::new()
+            .env_local(local_env_path.to_str().unwrap())
+            .watch();
+
+        let resource_output = env_folder.output(&mut factory).await.unwrap();
+        let _ = EnvVars::build(&resource_output).await.unwrap();
+
+        assert_eq!(
+            std::env::var("MY_VAR10").unwrap(),
+            "1",
+            "should load env var"
+        );
+
+        assert!(
+            !watch_env_file(&local_env_path, Vec::new()),
+            "should not watch the same file twice"
+        );
+
+        fs::write(&local_env_path, "MY_VAR10=2\nMY_VAR22=2\nMY_VAR23=1").unwrap();
+
+        // the report is refreshed once the env vars are reloaded
+        let mut reloaded = false;
+        for _ in 0..50 {
+            let report = LoadReport::get(&local_env_path).unwrap();
+            if report.loaded_keys.contains(&"MY_VAR23".to_string()) {
+                reloaded = true;
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        }
+
+        assert!(reloaded, "should reload env vars after the file changes");
+        assert_eq!(
+            std::env::var("MY_VAR10").unwrap(),
+            "2",
+            "should override env vars loaded from the file"
+        );
+        assert_eq!(
+            std::env::var("MY_VAR22").unwrap(),
+            "shell",
+            "should keep env vars set before loading the file"
+        );
+    }
+
+    #[tokio::test]
+    async fn works_if_folder_and_prod_file_are_paths() {
+        let mut factory = MockFactory::new(true);
+
+        const CONTENT: &str = "MY_VAR11=1";
+        const ENV_FOLDER: &str = "path_folder";
+        const ENV_PROD_FILE: &str = ".env-prod";
+
+        let env_path = factory.build_path().join(ENV_FOLDER).join(ENV_PROD_FILE);
+        fs::create_dir_all(env_path.parent().unwrap()).unwrap();
+        fs::write(&env_path, CONTENT).unwrap();
+
+        // Call plugin
+        let env_folder = EnvVars::new()
+            .folder_path(PathBuf::from(ENV_FOLDER))
+            .env_prod_path(Path::new(ENV_PROD_FILE));
+
+        let resource_output = env_folder.output(&mut factory).await.unwrap();
+        let output_folder = EnvVars::build(&resource_output).await.unwrap();
+
+        assert_eq!(
+            output_folder,
+            factory.storage_path().join(ENV_FOLDER),
+            "should return storage folder"
+        );
+        assert_eq!(
+            std::env::var("MY_VAR11").unwrap(),
+            "1",
+            "should load env var"
+        );
+    }
+
+    #[tokio::test]
+    async fn works_if_local_file_is_path() {
+        let mut factory = MockFactory::new(false);
+
+        const CONTENT: &str = "MY_VAR12=1";
+
+        let local_env_path = factory.build_path().join(".env-dev-path");
+        fs::write(&local_env_path, CONTENT).unwrap();
+
+        // Call plugin
+        let env_folder = EnvVars::new()
+            .env_local("random/.env-dev")
+            .env_local_path(&local_env_path);
+
+        let resource_output = env_folder.output(&mut factory).await.unwrap();
+        let output_folder = EnvVars::build(&resource_output).await.unwrap();
+
+        assert_eq!(
+            output_folder, local_env_path,
+            "should return local env path"
+        );
+        assert_eq!(
+            std::env::var("MY_VAR12").unwrap(),
+            "1",
+            "should load env var"
+        );
+    }
+
+    #[tokio::test]
+    async fn works_if_production_and_from_storage() {
+        let mut factory = MockFactory::new(true);
+
+        const CONTENT: &str = "MY_VAR13=1";
+        const ENV_FOLDER: &str = "storage_folder";
+        const ENV_PROD_FILE: &str = ".env-prod";
+
+        let env_path = factory.storage_path().join(ENV_FOLDER).join(ENV_PROD_FILE);
+        fs::create_dir_all(env_path.parent().unwrap()).unwrap();
+        fs::write(&env_path, CONTENT).unwrap();
+
+        assert!(
+            !factory.build_path().join(ENV_FOLDER).exists(),
+            "static folder should not exist"
+        );
+
+        // Call plugin
+        let env_folder = EnvVars::new()
+            .folder(ENV_FOLDER)
+            .env_prod(ENV_PROD_FILE)
+            .from_storage();
+
+        let resource_output = env_folder.output(&mut factory).await.unwrap();
+        let output_folder = EnvVars::build(&resource_output).await.unwrap();
+
+        assert_eq!(
+            output_folder,
+            factory.storage_path().join(ENV_FOLDER),
+            "should return storage folder"
+        );
+        assert_eq!(
+            std::env::var("MY_VAR13").unwrap(),
+            "1",
+            "should load env var"
+        );
+        assert_eq!(
+            LoadReport::get(&output_folder).unwrap().loaded_keys,
+            vec!["MY_VAR13".to_string()],
+            "should report by the returned resource"
+        );
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Cannot use an absolute path for a storage folder")]
+    async fn cannot_use_absolute_path_from_storage() {
+        let mut factory = MockFactory::new(true);
+        let env_folder = EnvVars::new();
+
+        let _ = env_folder
+            .folder("/etc")
+            .from_storage()
+            .output(&mut factory)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Cannot traverse out of the storage folder")]
+    async fn cannot_traverse_up_from_storage() {
+        let mut factory = MockFactory::new(true);
+
+        let password_file_path = factory.escape_path().join("passwd");
+        fs::create_dir_all(password_file_path.parent().unwrap()).unwrap();
+        fs::write(password_file_path, "qwerty").unwrap();
+
+        // Call plugin
+        let env_folder = EnvVars::new();
+
+        let _ = env_folder
+            .folder("../escape")
+            .from_storage()
+            .output(&mut factory)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "Cannot load env vars")]
+    async fn panics_if_production_and_from_storage_file_not_present() {
+        let mut factory = MockFactory::new(true);
+
+        // Call plugin
+        let env_folder = EnvVars::new().from_storage();
+
+        let output = env_folder.output(&mut factory).await.unwrap();
+        let _ = EnvVars::build(&output).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn reports_loaded_keys_without_values() {
+        let mut factory = MockFactory::new(false);
+
+        const CONTENT: &str = "MY_VAR14=secret\nMY_VAR15=secret";
+
+        std::env::set_var("MY_VAR15", "1");
+        let local_env_path = factory.build_path().join(".env-report");
+        fs::write(&local_env_path, CONTENT).unwrap();
+
+        // Call plugin
+        let env_folder = EnvVars::new()
+            .env_local(local_env_path.to_str().unwrap())
+            .required(&["MY_VAR14", "MY_VAR16"]);
+
+        let resource_output = env_folder.output(&mut factory).await.unwrap();
+        let output_folder = EnvVars::build(&resource_output).await.unwrap();
+        let report = LoadReport::get(&output_folder).unwrap();
+
+        assert_eq!(report.environment, "local");
+        assert_eq!(report.source_path, local_env_path);
+        assert!(report.file_found, "should find the env file");
+        assert_eq!(report.loaded_keys, vec!["MY_VAR14".to_string()]);
+        assert_eq!(report.missing_required, vec!["MY_VAR16".to_string()]);
+        assert_eq!(report.kept_existing, vec!["MY_VAR15".to_string()]);
+        assert!(
+            !format!("{report:?}").contains("secret"),
+            "should not contain values"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_keys_loaded_from_file() {
+        let factory = MockFactory::new(false);
+
+        let local_env_path = factory.build_path().join(".env-report-keys");
+        fs::write(&local_env_path, "MY_VAR17=1\nMY_VAR18=1").unwrap();
+
+        let report =
+            EnvVars::load_and_report(&local_env_path, &local_env_path, "local", &[], &[]).unwrap();
+
+        assert_eq!(
+            report.loaded_keys,
+            vec!["MY_VAR17".to_string(), "MY_VAR18".to_string()]
+        );
+        assert!(report.missing_required.is_empty());
+        assert!(
+            report.kept_existing.is_empty(),
+            "no key should be already set"
+        );
+    }
+
+    #[tokio::test]
+    async fn reports_missing_file() {
+        let mut factory = MockFactory::new(true);
+
+        // Call plugin
+        let env_folder = EnvVars::new()
+            .folder("missing_folder")
+            .from_storage()
+            .required(&["MY_VAR24"]);
+
+        let resource_output = env_folder.output(&mut factory).await.unwrap();
+        assert!(
+            EnvVars::build(&resource_output).await.is_err(),
+            "should fail to load env vars"
+        );
+
+        let env_dir = factory.storage_path().join("missing_folder");
+        let report = LoadReport::get(&env_dir).unwrap();
+
+        assert_eq!(report.environment, "production");
+        assert_eq!(report.source_path, env_dir.join(DEFAULT_ENV_PROD));
+        assert!(!report.file_found, "should not find the env file");
+        assert!(report.loaded_keys.is_empty());
+        assert_eq!(report.missing_required, vec!["MY_VAR24".to_string()]);
+    }
+
+    #[test]
+    fn reports_keys_loaded_before_a_parse_error() {
+        let temp_dir = Builder::new().prefix("env_folder").tempdir().unwrap();
+        let env_path = temp_dir.path().join(".env-report-broken");
+        fs::write(&env_path, "MY_VAR25=1\nMY VAR26=1\n").unwrap();
+
+        assert!(EnvVars::load_and_report(&env_path, &env_path, "local", &[], &[]).is_err());
+
+        let report = LoadReport::get(&env_path).unwrap();
+
+        assert!(report.file_found, "should find the env file");
+        assert_eq!(report.loaded_keys, vec!["MY_VAR25".to_string()]);
+    }
+
+    #[test]
+    fn last_path_setter_wins() {
+        let env_folder = EnvVars::new()
+            .folder_path(PathBuf::from("a"))
+            .folder("b")
+            .env_local("a/.env")
+            .env_local_path("b/.env")
+            .env_prod_path("a.env")
+            .env_prod("b.env");
+
+        assert_eq!(env_folder.config(), Path::new("b"));
+        assert_eq!(env_folder.env_file_path(None), PathBuf::from("b/.env"));
+        assert_eq!(
+            env_folder.env_file_path(Some(&PathBuf::from("dir"))),
+            PathBuf::from("dir/b.env")
+        );
+    }
 }